@@ -3,9 +3,11 @@
 //! Putting textures together, hopefully without wasting too much space.
 
 extern crate framing;
+extern crate rayon;
 
 use framing::{AsBytes, Image, Chunky};
-use std::{mem, ptr};
+use rayon::prelude::*;
+use std::{mem, ptr, usize};
 
 /// Stores images, and automatically stitches them together.
 ///
@@ -13,284 +15,808 @@ use std::{mem, ptr};
 /// space efficiency it's necessary to at least sort-of sort the frames in
 /// terms of decreasing size. Particularly good orders are by `width * height`
 /// and by `max(width, height)`, both in descending order.
+///
+/// A single atlas may span several *pages*. Each page grows on its own up to
+/// the configured `max_width`/`max_height`, at which point the next image that
+/// cannot be placed within the cap spills over onto a fresh page. This keeps
+/// every page within the texture-size limits imposed by the GPU.
 pub struct Atlas<T> {
-    bytes: Vec<u8>,
-    scratch: Vec<u8>,
-    width: usize,
-    height: usize,
     blank: T,
-    rects: Vec<Rect>
+    max_width: usize,
+    max_height: usize,
+    mode: Packing,
+    padding: usize,
+    extrude: usize,
+    pot: bool,
+    pages: Vec<Page>,
+    next_id: usize
 }
 
+/// Which algorithm an [`Atlas`] uses to decide where images go.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Packing {
+    /// The default. Keeps a list of disjoint free rectangles and, on each
+    /// placement, guillotine-splits the chosen one into up to three children.
+    /// Cheap, but fragments space for mixed sizes.
+    Guillotine,
+    /// Keeps a list of *maximal* free rectangles that may overlap, placing each
+    /// image by Best-Short-Side-Fit. Packs mixed sizes noticeably tighter at
+    /// the cost of a more expensive placement step.
+    MaxRects,
+    /// Divides each page into horizontal shelves, opening a new shelf whenever
+    /// an image won't fit on any existing one. Wastes more space on mixed
+    /// heights, but both allocation and freeing are close to O(1), which suits
+    /// glyph caches and other uniform-height content.
+    Shelf
+}
+
+/// An opaque handle to a single image placed in an [`Atlas`].
+///
+/// Hang on to the id returned by `add` and hand it back to `remove` when the
+/// image is no longer needed; the space it occupied is then reclaimed (see
+/// `remove` for how each packing mode does so).
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct AllocId(usize);
+
 impl<T> Atlas<T> {
-    /// Create a new, empty atlas.
+    /// Create a new, empty atlas with no size cap.
     ///
     /// The blank pixel will be used to represent the space that exists between
     /// images, in the almost certain case that 100% space utilization is not
     /// achieved.
+    ///
+    /// Without a cap every image ends up on a single page; use
+    /// [`with_limits`](#method.with_limits) to pack against a maximum texture
+    /// size instead.
     pub fn new(blank: T) -> Self {
+        Atlas::with_limits(blank, usize::MAX, usize::MAX)
+    }
+
+    /// Create a new, empty atlas whose pages never exceed `max_width` by
+    /// `max_height`.
+    ///
+    /// Any image larger than the cap in either dimension simply won't fit, and
+    /// `add` will keep opening empty pages for it in vain, so it's on the
+    /// caller to ensure individual images are no larger than a single page.
+    pub fn with_limits(blank: T, max_width: usize, max_height: usize) -> Self {
         Atlas {
-            bytes: Vec::new(),
-            scratch: Vec::new(),
-            width: 0,
-            height: 0,
             blank: blank,
-            rects: Vec::new()
+            max_width: max_width,
+            max_height: max_height,
+            mode: Packing::Guillotine,
+            padding: 0,
+            extrude: 0,
+            pot: false,
+            pages: Vec::new(),
+            next_id: 0
         }
     }
 
+    /// Insert a `padding`-pixel gutter around every placed image, returning the
+    /// atlas for chaining.
+    ///
+    /// The gutter is accounted for during placement, so the coordinates `add`
+    /// returns still point at the true sprite origin, not at the padded slot.
+    pub fn padding(mut self, padding: usize) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    /// Replicate each sprite's border pixels `extrude` pixels deep into its
+    /// gutter, returning the atlas for chaining.
+    ///
+    /// This bleeds the sprite's own edge colour — rather than the blank pixel —
+    /// into the surrounding padding, which stops neighbouring sprites from
+    /// leaking in under bilinear sampling. Clamped to the configured
+    /// [`padding`](#method.padding); a larger value simply fills the whole
+    /// gutter.
+    pub fn extrude(mut self, extrude: usize) -> Self {
+        self.extrude = extrude;
+        self
+    }
+
+    /// Round the dimensions of each extracted page up to the next power of two,
+    /// returning the atlas for chaining. The extra area is filled with the
+    /// blank pixel.
+    pub fn power_of_two(mut self, pot: bool) -> Self {
+        self.pot = pot;
+        self
+    }
+
+    /// Select the packing algorithm, returning the atlas for chaining:
+    ///
+    /// ```ignore
+    /// let atlas = Atlas::new(blank).packing(Packing::MaxRects);
+    /// ```
+    ///
+    /// Only meaningful before the first `add`; switching modes on a populated
+    /// atlas leaves existing placements untouched but mixes free-list shapes,
+    /// so do it up front.
+    pub fn packing(mut self, mode: Packing) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Create a new, empty atlas whose pages are capped at `limit` in both
+    /// dimensions, matching the square texture-size limits GPUs usually report.
+    pub fn with_texture_limit(blank: T, limit: usize) -> Self {
+        Atlas::with_limits(blank, limit, limit)
+    }
+
+    /// The number of pages the atlas currently spans.
+    pub fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
     /// Adds an image to the atlas, placing it appropriately.
     ///
-    /// The return value is the location of the image within the atlas. If the
-    /// image is of zero size, then the coordinates `(0, 0)` will be returned,
-    /// which you most likely won't need to special-case, since it is
-    /// *technically* valid.
-    pub fn add<U>(&mut self, image: U) -> (usize, usize)
+    /// The return value is an opaque [`AllocId`] handle — hand it to `remove`
+    /// to reclaim the space later — together with the page the image landed on
+    /// and its location within that page. If the image is of zero size, then a
+    /// fresh id and `(0, 0)` will be returned, which you most likely won't need
+    /// to special-case, since it is *technically* valid.
+    pub fn add<U>(&mut self, image: U) -> (AllocId, usize, usize, usize)
     where
         T: AsBytes + Clone + Sync + 'static,
         U: Image<Pixel = T> + Sync
     {
-        let (w, h) = (image.width(), image.height());
+        let id = AllocId(self.next_id);
+        self.next_id += 1;
 
-        if w == 0 || h == 0 {
-            return (0, 0);
+        if image.width() == 0 || image.height() == 0 {
+            return (id, 0, 0, 0);
         }
 
-        if self.width == 0 || self.height == 0 {
-            self.bytes.reserve(T::width() * w * h);
-            self.width = w;
-            self.height = h;
+        // Wrap the sprite in its gutter once; the packer then deals purely in
+        // padded slots, and we add the padding back on to report the true
+        // sprite origin.
+        let pad = self.padding;
+        let padded = Padded {
+            image: &image,
+            blank: self.blank.clone(),
+            padding: pad,
+            extrude: self.extrude
+        };
+        let (w, h) = (padded.width(), padded.height());
+        let area = w * h;
+
+        // Try to squeeze the image onto a page that already exists, skipping any
+        // page that has already turned away something no larger than this one.
+        for (page, slot) in self.pages.iter_mut().enumerate() {
+            if area >= slot.smallest_miss {
+                continue;
+            }
 
-            for (_, _, pixel) in framing::iter(&image) {
-                self.bytes.extend_from_slice(T::Bytes::from(pixel).as_ref())
+            if let Some((x, y)) =
+                slot.try_add(&padded, w, h, &self.blank,
+                             self.max_width, self.max_height, self.mode)
+            {
+                slot.used_rects.push((Rect { x: x, y: y, w: w, h: h }, id));
+                return (id, page, x + pad, y + pad);
             }
+        }
 
-            return (0, 0);
+        // None of the existing pages had room, so start a fresh one.
+        let mut slot = Page::new();
+        let placed = slot.try_add(&padded, w, h, &self.blank,
+                                  self.max_width, self.max_height, self.mode);
+        let page = self.pages.len();
+        if let Some((x, y)) = placed {
+            slot.used_rects.push((Rect { x: x, y: y, w: w, h: h }, id));
         }
+        self.pages.push(slot);
 
-        let result = self.rects.iter()
-            .enumerate()
-            .filter(|&(_, rect)| w <= rect.w && h <= rect.h)
-            .min_by_key(|&(_, rect)| {
-                let (dw, dh) = (rect.w - w, rect.h - h);
-                if dh < dw { dh } else { dw }
+        match placed {
+            Some((x, y)) => (id, page, x + pad, y + pad),
+            // The image is larger than a whole page; there's nothing sensible to
+            // do but report the origin of the (otherwise empty) page.
+            None => (id, page, 0, 0)
+        }
+    }
+
+    /// Removes a previously-placed image, returning its space to the free list.
+    ///
+    /// The freed rectangle is merged with any adjacent free rectangles so that
+    /// a later `add` sees as large a contiguous gap as possible. In
+    /// `Packing::Shelf` mode no free rectangle is made: the shelf's cursor
+    /// rewinds only if the image was the rightmost on its shelf, and the shelf
+    /// becomes reusable once nothing remains on it. Returns `true`
+    /// if the id referred to a live placement, and `false` if it was unknown or
+    /// already removed.
+    pub fn remove(&mut self, id: AllocId) -> bool {
+        for slot in &mut self.pages {
+            if let Some(i) = slot.used_rects.iter().position(|&(_, r)| r == id) {
+                let (rect, _) = slot.used_rects.remove(i);
+                slot.used_area -= rect.w * rect.h;
+                // A freed gap might let a previously-rejected image fit again.
+                slot.smallest_miss = usize::MAX;
+                if self.mode == Packing::Shelf {
+                    slot.release_shelf(&rect);
+                } else {
+                    slot.rects.push(rect);
+                    slot.coalesce();
+                }
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// The total area, in pixels, currently occupied by placed images across
+    /// every page.
+    pub fn used_area(&self) -> usize {
+        self.pages.iter().map(|page| page.used_area).sum()
+    }
+
+    /// The total unoccupied area, in pixels, across every page.
+    pub fn free_area(&self) -> usize {
+        self.total_area() - self.used_area()
+    }
+
+    /// The fraction of the atlas's area that is occupied, between `0.0` and
+    /// `1.0`. An empty atlas is defined to be fully occupied (`1.0`) so callers
+    /// needn't guard against a division by zero.
+    pub fn occupancy(&self) -> f64 {
+        let total = self.total_area();
+        if total == 0 {
+            1.0
+        } else {
+            self.used_area() as f64 / total as f64
+        }
+    }
+
+    fn total_area(&self) -> usize {
+        self.pages.iter().map(|page| page.width * page.height).sum()
+    }
+
+    /// Extract each page as its own [`Chunky<T>`], at the page's own size, or
+    /// rounded up to powers of two if [`power_of_two`](#method.power_of_two)
+    /// is set.
+    ///
+    /// Pages are returned in the same order their indices are handed out by
+    /// `add`.
+    pub fn into_pages(self) -> Vec<Chunky<T>> where T: AsBytes + Clone {
+        let (pot, blank) = (self.pot, self.blank.clone());
+        self.pages
+            .into_iter()
+            .map(|mut page| {
+                if pot {
+                    page.pad_to_pot(&blank);
+                }
+                Chunky::from_bytes(page.width, page.height, page.bytes)
             })
-            .map(|(i, rect)| (i, rect.clone()));
+            .collect()
+    }
+}
 
-        if let Some((i, rect)) = result {
-            self.rects.remove(i);
+/// A single page of an [`Atlas`], holding its own backing buffer and free-rect
+/// list. All of the actual packing happens here; the atlas just decides which
+/// page an image should go on.
+struct Page {
+    bytes: Vec<u8>,
+    scratch: Vec<u8>,
+    width: usize,
+    height: usize,
+    rects: Vec<Rect>,
+    /// Every occupied region, tagged with the handle that owns it, so that
+    /// `remove` can find and reclaim it.
+    used_rects: Vec<(Rect, AllocId)>,
+    /// Horizontal shelves, used only by `Packing::Shelf`.
+    shelves: Vec<Shelf>,
+    /// Sum of the areas of every image placed on this page.
+    used_area: usize,
+    /// Area of the smallest image this page has turned away since space was
+    /// last freed on it. Anything at least this large can be skipped without
+    /// scanning the free-rect list.
+    smallest_miss: usize
+}
 
-            if rect.w != w {
-                self.rects.push(Rect {
-                    x: rect.x + w,
-                    y: rect.y,
-                    w: rect.w - w,
-                    h: h
-                });
+impl Page {
+    fn new() -> Self {
+        Page {
+            bytes: Vec::new(),
+            scratch: Vec::new(),
+            width: 0,
+            height: 0,
+            rects: Vec::new(),
+            used_rects: Vec::new(),
+            shelves: Vec::new(),
+            used_area: 0,
+            smallest_miss: usize::MAX
+        }
+    }
+
+    /// Repeatedly merge pairs of free rectangles that share a full edge, until
+    /// no more merges are possible. Keeps the free list from fragmenting into
+    /// slivers as images come and go.
+    fn coalesce(&mut self) {
+        let mut merged = true;
+        while merged {
+            merged = false;
+
+            'outer: for i in 0..self.rects.len() {
+                for j in (i + 1)..self.rects.len() {
+                    if let Some(union) = self.rects[i].merge(&self.rects[j]) {
+                        self.rects[i] = union;
+                        self.rects.remove(j);
+                        merged = true;
+                        break 'outer;
+                    }
+                }
             }
+        }
+    }
 
-            if rect.h != h {
-                self.rects.push(Rect {
-                    x: rect.x,
-                    y: rect.y + h,
-                    w: w,
-                    h: rect.h - h
-                });
+    /// Record that a `w` by `h` image didn't fit, and return `None`.
+    fn miss(&mut self, w: usize, h: usize) -> Option<(usize, usize)> {
+        let area = w * h;
+        if area < self.smallest_miss {
+            self.smallest_miss = area;
+        }
+        None
+    }
+
+    fn try_add<T, U>(
+        &mut self,
+        image: &U,
+        w: usize,
+        h: usize,
+        blank: &T,
+        max_width: usize,
+        max_height: usize,
+        mode: Packing
+    ) -> Option<(usize, usize)>
+    where
+        T: AsBytes + Clone + Sync + 'static,
+        U: Image<Pixel = T> + Sync
+    {
+        if mode == Packing::Shelf {
+            return self.shelf_add(image, w, h, blank, max_width, max_height);
+        }
+
+        if self.width == 0 || self.height == 0 {
+            if w > max_width || h > max_height {
+                return self.miss(w, h);
             }
 
-            if rect.w != w && rect.h != h {
-                self.rects.push(Rect {
-                    x: rect.x + w,
-                    y: rect.y + h,
-                    w: rect.w - w,
-                    h: rect.h - h
-                });
+            self.bytes.reserve(T::width() * w * h);
+            self.width = w;
+            self.height = h;
+
+            for (_, _, pixel) in framing::iter(image) {
+                self.bytes.extend_from_slice(T::Bytes::from(pixel).as_ref())
             }
 
-            // The image fits!
-            for y in 0..h {
-            for x in 0..w {
-                let i = T::width() * (self.width * (rect.y + y) + (rect.x + x));
-                let p = T::Bytes::from(unsafe {
-                    image.pixel(x, y)
-                });
+            self.used_area += w * h;
+            return Some((0, 0));
+        }
 
-                unsafe {
-                    ptr::copy_nonoverlapping(
-                        p.as_ref().as_ptr(),
-                        self.bytes.as_mut_ptr().offset(i as isize),
-                        T::width()
-                    )
-                }
-            }}
+        let placed = match mode {
+            Packing::Guillotine => self.place_guillotine(image, w, h),
+            Packing::MaxRects => self.place_maxrects(image, w, h),
+            // Shelf placement is handled before the free-rect path above.
+            Packing::Shelf => unreachable!()
+        };
 
-            (rect.x, rect.y)
-        } else {
-            // The image doesn't fit.
+        if let Some(xy) = placed {
+            return Some(xy);
+        }
 
+        // The image doesn't fit into any existing gap; grow the page, provided
+        // doing so stays within the texture-size cap.
+        {
             if self.height <= self.width {
                 // Our atlas is wider than it is tall, so the image is put at
                 // the bottom of the atlas, to make it more square.
 
-                if self.width > w {
+                let (old_width, old_height) = (self.width, self.height);
+                let new_width = if w > old_width { w } else { old_width };
+                if new_width > max_width || old_height + h > max_height {
+                    return self.miss(w, h);
+                }
+
+                if old_width > w {
                     self.rects.push(Rect {
                         x: w,
-                        y: self.height,
-                        w: self.width - w,
+                        y: old_height,
+                        w: old_width - w,
                         h: h
                     });
-                } else if self.width < w {
+                } else if old_width < w {
                     self.rects.push(Rect {
-                        x: self.width,
+                        x: old_width,
                         y: 0,
-                        w: w - self.width,
-                        h: self.height
+                        w: w - old_width,
+                        h: old_height
                     });
                 }
 
-                if w <= self.width {
-                    // The image is already wide enough.
-
-                    self.bytes.reserve(T::width() * self.width * h);
-
-                    for y in self.height..(self.height + h) {
-                        for x in 0..w {
-                            let pixel = T::Bytes::from(unsafe {
-                                image.pixel(x, y)
-                            });
-                            self.bytes.extend_from_slice(pixel.as_ref());
-                        }
-                        for _ in w..self.width {
-                            let pixel = T::Bytes::from(self.blank.clone());
-                            self.bytes.extend_from_slice(pixel.as_ref());
-                        }
-                    }
-
-                    self.height = self.height + h;
-                } else {
-                    // We need to make the image wider.
-
-                    let cap = T::width() * (self.height + h) * w;
-                    self.scratch.clear();
-                    self.scratch.reserve(cap);
-
-                    for chunk in self.bytes.chunks(T::width() * self.width) {
-                        self.scratch.extend_from_slice(chunk);
-                        for _ in self.width..w {
-                            let pixel = T::Bytes::from(self.blank.clone());
-                            self.scratch.extend_from_slice(pixel.as_ref());
-                        }
-                    }
-
-                    for y in 0..h {
-                        for x in 0..w {
-                            let pixel = T::Bytes::from(unsafe {
-                                image.pixel(x, y)
-                            });
-                            self.scratch.extend_from_slice(pixel.as_ref());
-                        }
-                    }
-
-                    mem::swap(&mut self.bytes, &mut self.scratch);
-                    self.width = w;
-                    self.height = self.height + h;
-                }
+                // Grow the canvas downwards (and wider if needed), then blit the
+                // sprite row-wise into its freshly-cleared home at the bottom.
+                self.ensure_size(new_width, old_height + h, blank);
+                self.blit(image, w, h, 0, old_height);
 
-                (0, self.height)
+                self.used_area += w * h;
+                Some((0, old_height))
             } else {
                 // Our atlas is taller than it is wide, so the image is put to
                 // the right of the atlas, to make it more square.
 
-                if self.height > h {
+                let (old_width, old_height) = (self.width, self.height);
+                let new_height = if old_height <= h { h } else { old_height };
+                let new_width = old_width + w;
+                if new_width > max_width || new_height > max_height {
+                    return self.miss(w, h);
+                }
+
+                if old_height > h {
                     self.rects.push(Rect {
-                        x: self.width,
+                        x: old_width,
                         y: h,
                         w: w,
-                        h: self.height - h
+                        h: old_height - h
                     });
-                } else if self.height < h {
+                } else if old_height < h {
                     self.rects.push(Rect {
                         x: 0,
-                        y: self.height,
-                        w: self.width,
-                        h: h - self.height
+                        y: old_height,
+                        w: old_width,
+                        h: h - old_height
                     });
                 }
 
-                let new_height = if self.height <= h { h } else { self.height };
-                let new_width = self.width + w;
-
-                let cap = T::width() * new_width * new_height;
-                self.scratch.clear();
-                self.scratch.reserve(cap);
-
-                for (y, chunk) in
-                    self.bytes
-                        .chunks(T::width() * self.width)
-                        .enumerate()
-                {
-                    self.scratch.extend_from_slice(chunk);
-                    if y < h {
-                        for x in 0..w {
-                            let pixel = T::Bytes::from(unsafe {
-                                image.pixel(x, y)
-                            });
-                            self.scratch.extend_from_slice(pixel.as_ref());
-                        }
-                    } else {
-                        for _ in 0..w {
-                            let pixel = T::Bytes::from(self.blank.clone());
-                            self.scratch.extend_from_slice(pixel.as_ref());
-                        }
-                    }
-                }
+                // Grow the canvas to the right (and taller if needed), then blit
+                // the sprite row-wise into its freshly-cleared home.
+                self.ensure_size(new_width, new_height, blank);
+                self.blit(image, w, h, old_width, 0);
 
-                for y in self.height..h {
-                    for _ in 0..self.width {
-                        let pixel = T::Bytes::from(self.blank.clone());
-                        self.scratch.extend_from_slice(pixel.as_ref());
-                    }
-                    for x in 0..w {
-                        let pixel = T::Bytes::from(unsafe {
-                            image.pixel(x, y)
-                        });
-                        self.scratch.extend_from_slice(pixel.as_ref());
-                    }
-                }
+                self.used_area += w * h;
+                Some((old_width, 0))
+            }
+        }
+    }
 
-                mem::swap(&mut self.bytes, &mut self.scratch);
-                self.width = new_width;
-                self.height = new_height;
+    /// Copy a `w` by `h` image into the backing buffer with its top-left corner
+    /// at `(ox, oy)`. The destination must already be large enough.
+    ///
+    /// Each output row is a contiguous slice, and because those slices are
+    /// disjoint we hand them to rayon and fill them in parallel. Within a row,
+    /// pixels are still read from the image and copied one at a time.
+    fn blit<T, U>(&mut self, image: &U, w: usize, h: usize, ox: usize, oy: usize)
+    where
+        T: AsBytes + Sync,
+        U: Image<Pixel = T> + Sync
+    {
+        let bpp = T::width();
+        let stride = bpp * self.width;
+        let (left, right) = (ox * bpp, (ox + w) * bpp);
+        let start = stride * oy;
+        let region = &mut self.bytes[start..start + stride * h];
+
+        region.par_chunks_mut(stride).enumerate().for_each(|(y, row)| {
+            let dst = &mut row[left..right];
+            for x in 0..w {
+                let p = T::Bytes::from(unsafe { image.pixel(x, y) });
+                dst[x * bpp..(x + 1) * bpp].copy_from_slice(p.as_ref());
+            }
+        });
+    }
+
+    /// Guillotine placement: find the tightest-fitting free rect, split it into
+    /// up to three disjoint children, and blit the image there.
+    fn place_guillotine<T, U>(&mut self, image: &U, w: usize, h: usize)
+        -> Option<(usize, usize)>
+    where
+        T: AsBytes + Sync,
+        U: Image<Pixel = T> + Sync
+    {
+        let result = self.rects.iter()
+            .enumerate()
+            .filter(|&(_, rect)| w <= rect.w && h <= rect.h)
+            .min_by_key(|&(_, rect)| {
+                let (dw, dh) = (rect.w - w, rect.h - h);
+                if dh < dw { dh } else { dw }
+            })
+            .map(|(i, rect)| (i, rect.clone()));
+
+        let (i, rect) = match result {
+            Some(found) => found,
+            None => return None
+        };
+
+        self.rects.remove(i);
+
+        if rect.w != w {
+            self.rects.push(Rect {
+                x: rect.x + w,
+                y: rect.y,
+                w: rect.w - w,
+                h: h
+            });
+        }
+
+        if rect.h != h {
+            self.rects.push(Rect {
+                x: rect.x,
+                y: rect.y + h,
+                w: w,
+                h: rect.h - h
+            });
+        }
+
+        if rect.w != w && rect.h != h {
+            self.rects.push(Rect {
+                x: rect.x + w,
+                y: rect.y + h,
+                w: rect.w - w,
+                h: rect.h - h
+            });
+        }
+
+        self.blit(image, w, h, rect.x, rect.y);
+        self.used_area += w * h;
+        Some((rect.x, rect.y))
+    }
+
+    /// MaxRects placement: pick the free rect minimizing the Best-Short-Side-Fit
+    /// score, then split every free rect that overlaps the newly-occupied
+    /// region into up to four maximal leftovers and prune contained rects.
+    fn place_maxrects<T, U>(&mut self, image: &U, w: usize, h: usize)
+        -> Option<(usize, usize)>
+    where
+        T: AsBytes + Sync,
+        U: Image<Pixel = T> + Sync
+    {
+        let best = self.rects.iter()
+            .filter(|rect| w <= rect.w && h <= rect.h)
+            .min_by_key(|rect| {
+                let (dw, dh) = (rect.w - w, rect.h - h);
+                let short = if dw < dh { dw } else { dh };
+                let long = if dw < dh { dh } else { dw };
+                (short, long)
+            })
+            .map(|rect| (rect.x, rect.y));
+
+        let (px, py) = match best {
+            Some(origin) => origin,
+            None => return None
+        };
+
+        // The region the image now occupies.
+        let p = Rect { x: px, y: py, w: w, h: h };
+
+        let mut leftovers = Vec::new();
+        self.rects.retain(|f| {
+            if !f.intersects(&p) {
+                return true;
+            }
+
+            // Left of P.
+            if p.x > f.x {
+                leftovers.push(Rect { x: f.x, y: f.y, w: p.x - f.x, h: f.h });
+            }
+            // Right of P.
+            if p.x + p.w < f.x + f.w {
+                leftovers.push(Rect {
+                    x: p.x + p.w, y: f.y, w: (f.x + f.w) - (p.x + p.w), h: f.h
+                });
+            }
+            // Above P.
+            if p.y > f.y {
+                leftovers.push(Rect { x: f.x, y: f.y, w: f.w, h: p.y - f.y });
+            }
+            // Below P.
+            if p.y + p.h < f.y + f.h {
+                leftovers.push(Rect {
+                    x: f.x, y: p.y + p.h, w: f.w, h: (f.y + f.h) - (p.y + p.h)
+                });
+            }
+
+            false
+        });
+
+        self.rects.extend(leftovers);
+        self.prune_contained();
+
+        self.blit(image, w, h, px, py);
+        self.used_area += w * h;
+        Some((px, py))
+    }
+
+    /// Drop any free rect that is wholly contained within another, preserving
+    /// the MaxRects invariant that no free rect is redundant.
+    fn prune_contained(&mut self) {
+        let mut i = 0;
+        while i < self.rects.len() {
+            let contained = self.rects.iter().enumerate().any(|(j, other)| {
+                j != i && other.contains(&self.rects[i])
+            });
+
+            if contained {
+                self.rects.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// Grow the backing buffer so it is at least `nw` by `nh`, preserving the
+    /// existing top-left content and filling the newly-exposed area with the
+    /// blank pixel. A no-op if the buffer is already large enough.
+    fn ensure_size<T>(&mut self, nw: usize, nh: usize, blank: &T)
+    where
+        T: AsBytes + Clone
+    {
+        if nw <= self.width && nh <= self.height {
+            return;
+        }
+
+        let nw = if nw > self.width { nw } else { self.width };
+        let nh = if nh > self.height { nh } else { self.height };
+        let bpp = T::width();
+        let stride = bpp * self.width;
+        let row = blank_row(blank, nw);
+
+        self.scratch.clear();
+        self.scratch.reserve(bpp * nw * nh);
+
+        for y in 0..nh {
+            if y < self.height {
+                self.scratch.extend_from_slice(&self.bytes[stride * y..][..stride]);
+                self.scratch.extend_from_slice(&row[..bpp * (nw - self.width)]);
+            } else {
+                self.scratch.extend_from_slice(&row[..bpp * nw]);
+            }
+        }
+
+        mem::swap(&mut self.bytes, &mut self.scratch);
+        self.width = nw;
+        self.height = nh;
+    }
+
+    /// Grow the page so both dimensions are powers of two, blank-filling the
+    /// extra area. A no-op for an empty page.
+    fn pad_to_pot<T>(&mut self, blank: &T)
+    where
+        T: AsBytes + Clone
+    {
+        if self.width == 0 || self.height == 0 {
+            return;
+        }
+
+        let nw = self.width.next_power_of_two();
+        let nh = self.height.next_power_of_two();
+        self.ensure_size(nw, nh, blank);
+    }
 
-                (self.width, 0)
+    /// Shelf placement: walk the shelves left-to-right for one whose height and
+    /// remaining width fit the image, opening a fresh shelf at the bottom when
+    /// none do.
+    fn shelf_add<T, U>(
+        &mut self,
+        image: &U,
+        w: usize,
+        h: usize,
+        blank: &T,
+        max_width: usize,
+        max_height: usize
+    ) -> Option<(usize, usize)>
+    where
+        T: AsBytes + Clone + Sync + 'static,
+        U: Image<Pixel = T> + Sync
+    {
+        // Reuse an existing (or wholly-freed) shelf if one can take the image.
+        for i in 0..self.shelves.len() {
+            let (y, height, free) = {
+                let shelf = &self.shelves[i];
+                (shelf.y, shelf.height, shelf.free)
+            };
+            let cursor = if free { 0 } else { self.shelves[i].cursor };
+            if h <= height && cursor + w <= max_width {
+                self.ensure_size(cursor + w, y + height, blank);
+                self.blit(image, w, h, cursor, y);
+                self.shelves[i].cursor = cursor + w;
+                self.shelves[i].free = false;
+                self.used_area += w * h;
+                return Some((cursor, y));
             }
         }
+
+        // Otherwise open a new shelf below the existing ones.
+        let y: usize = self.shelves.iter().map(|s| s.height).sum();
+        if w > max_width || y + h > max_height {
+            return self.miss(w, h);
+        }
+
+        self.ensure_size(w, y + h, blank);
+        self.blit(image, w, h, 0, y);
+        self.shelves.push(Shelf { y: y, height: h, cursor: w, free: false });
+        self.used_area += w * h;
+        Some((0, y))
+    }
+
+    /// Release a shelf-allocated region: rewind the owning shelf's cursor if
+    /// the freed image was the last one on it, and mark the shelf reusable once
+    /// nothing remains on it.
+    fn release_shelf(&mut self, rect: &Rect) {
+        for i in 0..self.shelves.len() {
+            let (sy, sh) = (self.shelves[i].y, self.shelves[i].height);
+            if rect.y < sy || rect.y >= sy + sh {
+                continue;
+            }
+
+            if rect.x + rect.w == self.shelves[i].cursor {
+                self.shelves[i].cursor = rect.x;
+            }
+
+            let still_used = self.used_rects.iter()
+                .any(|entry| entry.0.y >= sy && entry.0.y < sy + sh);
+            if !still_used {
+                self.shelves[i].cursor = 0;
+                self.shelves[i].free = true;
+            }
+
+            return;
+        }
     }
 }
 
-impl<T> Into<Chunky<T>> for Atlas<T> where T: AsBytes {
+/// Converts the atlas into a single [`Chunky<T>`].
+///
+/// This only ever yields the *first* page: an atlas that spilled onto more
+/// than one page (see [`Atlas::with_limits`]) would lose everything past page
+/// zero, so a capped atlas should be drained with
+/// [`into_pages`](Atlas::into_pages) instead. In debug builds a multi-page
+/// atlas trips an assertion here rather than silently dropping pages.
+impl<T> Into<Chunky<T>> for Atlas<T> where T: AsBytes + Clone {
     fn into(self) -> Chunky<T> {
-        Chunky::from_bytes(self.width, self.height, self.bytes)
+        debug_assert!(
+            self.pages.len() <= 1,
+            "converting a multi-page atlas via Into<Chunky> drops all but the \
+             first page; use into_pages() instead"
+        );
+        let (pot, blank) = (self.pot, self.blank.clone());
+        match self.pages.into_iter().next() {
+            Some(mut page) => {
+                if pot {
+                    page.pad_to_pot(&blank);
+                }
+                Chunky::from_bytes(page.width, page.height, page.bytes)
+            }
+            None => Chunky::from_bytes(0, 0, Vec::new())
+        }
     }
 }
 
+/// Views the atlas as an image.
+///
+/// Like [`Into<Chunky>`](#impl-Into<Chunky<T>>-for-Atlas<T>), this reports only
+/// the *first* page's dimensions and pixels; measuring or sampling a capped
+/// atlas that spilled onto several pages therefore ignores every page but the
+/// first. Iterate [`into_pages`](Atlas::into_pages) to reach the rest. Debug
+/// builds assert that at most one page exists.
 impl<T> Image for Atlas<T> where T: AsBytes {
     type Pixel = T;
 
     fn width(&self) -> usize {
-        self.width
+        debug_assert!(self.pages.len() <= 1, "Atlas spans multiple pages; use into_pages()");
+        self.pages.first().map(|page| page.width).unwrap_or(0)
     }
 
     fn height(&self) -> usize {
-        self.height
+        debug_assert!(self.pages.len() <= 1, "Atlas spans multiple pages; use into_pages()");
+        self.pages.first().map(|page| page.height).unwrap_or(0)
     }
 
     unsafe fn pixel(&self, x: usize, y: usize) -> Self::Pixel {
-        let off = T::width() * (y * self.width + x);
+        debug_assert!(self.pages.len() <= 1, "Atlas spans multiple pages; use into_pages()");
+        let page = &self.pages[0];
+        let off = T::width() * (y * page.width + x);
         let mut bytes = T::Bytes::default();
 
         ptr::copy_nonoverlapping(
-            self.bytes.as_ptr().offset(off as isize),
+            page.bytes.as_ptr().offset(off as isize),
             bytes.as_mut().as_mut_ptr(),
             T::width()
         );
@@ -299,6 +825,90 @@ impl<T> Image for Atlas<T> where T: AsBytes {
     }
 }
 
+/// A sprite wrapped in a padding gutter, presented to the packer as a single
+/// larger image. Interior pixels pass through unchanged; gutter pixels within
+/// `extrude` of an edge replicate that edge's colour, and the rest are blank.
+struct Padded<'a, U: 'a, T> {
+    image: &'a U,
+    blank: T,
+    padding: usize,
+    extrude: usize
+}
+
+impl<'a, U, T> Image for Padded<'a, U, T>
+where
+    U: Image<Pixel = T>,
+    T: Clone
+{
+    type Pixel = T;
+
+    fn width(&self) -> usize {
+        self.image.width() + 2 * self.padding
+    }
+
+    fn height(&self) -> usize {
+        self.image.height() + 2 * self.padding
+    }
+
+    unsafe fn pixel(&self, x: usize, y: usize) -> T {
+        let (w, h) = (self.image.width(), self.image.height());
+        let p = self.padding as isize;
+        let (sx, sy) = (x as isize - p, y as isize - p);
+
+        // How far outside the sprite this pixel lies, per axis.
+        let dx = if sx < 0 {
+            (-sx) as usize
+        } else if sx as usize >= w {
+            sx as usize - (w - 1)
+        } else {
+            0
+        };
+        let dy = if sy < 0 {
+            (-sy) as usize
+        } else if sy as usize >= h {
+            sy as usize - (h - 1)
+        } else {
+            0
+        };
+
+        if dx == 0 && dy == 0 {
+            self.image.pixel(sx as usize, sy as usize)
+        } else if dx <= self.extrude && dy <= self.extrude {
+            // Within the extrusion band: sample the nearest edge pixel.
+            let cx = if sx < 0 { 0 } else if sx as usize >= w { w - 1 } else { sx as usize };
+            let cy = if sy < 0 { 0 } else if sy as usize >= h { h - 1 } else { sy as usize };
+            self.image.pixel(cx, cy)
+        } else {
+            self.blank.clone()
+        }
+    }
+}
+
+/// Build a contiguous byte buffer of `pixels` blank pixels, suitable for
+/// filling gutters and padding in a single `copy_from_slice`/`extend_from_slice`
+/// rather than one pixel at a time.
+fn blank_row<T>(blank: &T, pixels: usize) -> Vec<u8>
+where
+    T: AsBytes + Clone
+{
+    let one = T::Bytes::from(blank.clone());
+    let one = one.as_ref();
+    let mut row = Vec::with_capacity(one.len() * pixels);
+    for _ in 0..pixels {
+        row.extend_from_slice(one);
+    }
+    row
+}
+
+/// A single horizontal shelf within a page: a band of fixed height whose items
+/// are laid out left-to-right behind an advancing cursor.
+struct Shelf {
+    y: usize,
+    height: usize,
+    cursor: usize,
+    free: bool
+}
+
 #[derive(Clone)]
 struct Rect {
     x: usize,
@@ -306,3 +916,51 @@ struct Rect {
     w: usize,
     h: usize
 }
+
+impl Rect {
+    /// If `self` and `other` are flush along a shared edge — same width and
+    /// column, stacked vertically, or same height and row, side by side — this
+    /// returns the single rectangle that covers both. Otherwise `None`.
+    fn merge(&self, other: &Rect) -> Option<Rect> {
+        if self.x == other.x && self.w == other.w {
+            if self.y + self.h == other.y {
+                return Some(Rect {
+                    x: self.x, y: self.y, w: self.w, h: self.h + other.h
+                });
+            }
+            if other.y + other.h == self.y {
+                return Some(Rect {
+                    x: self.x, y: other.y, w: self.w, h: self.h + other.h
+                });
+            }
+        }
+
+        if self.y == other.y && self.h == other.h {
+            if self.x + self.w == other.x {
+                return Some(Rect {
+                    x: self.x, y: self.y, w: self.w + other.w, h: self.h
+                });
+            }
+            if other.x + other.w == self.x {
+                return Some(Rect {
+                    x: other.x, y: self.y, w: self.w + other.w, h: self.h
+                });
+            }
+        }
+
+        None
+    }
+
+    /// Whether this rectangle overlaps `other` in a region of non-zero area.
+    fn intersects(&self, other: &Rect) -> bool {
+        self.x < other.x + other.w && other.x < self.x + self.w &&
+        self.y < other.y + other.h && other.y < self.y + self.h
+    }
+
+    /// Whether this rectangle wholly contains `other`.
+    fn contains(&self, other: &Rect) -> bool {
+        self.x <= other.x && self.y <= other.y &&
+        other.x + other.w <= self.x + self.w &&
+        other.y + other.h <= self.y + self.h
+    }
+}